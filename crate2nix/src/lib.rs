@@ -0,0 +1,25 @@
+//! crate2nix turns a Cargo project's resolved dependency graph into a `Cargo.nix` that builds
+//! it reproducibly with nix.
+
+pub mod nix_build;
+pub mod prefetch;
+pub mod render;
+pub mod resolve;
+
+use std::path::PathBuf;
+
+use crate::prefetch::GitFetcher;
+
+/// Configuration shared across generation: resolving the dependency graph, prefetching
+/// hashes, and rendering the final `Cargo.nix`.
+#[derive(Debug, Clone)]
+pub struct GenerateConfig {
+    /// Where to read/write the cache of already-prefetched hashes.
+    pub crate_hashes_json: PathBuf,
+    /// Which nix fetcher `prefetch` should target for git dependencies; see [`GitFetcher`].
+    pub git_fetcher: GitFetcher,
+    /// When set, `prefetch` also downloads every crates.io and git source into this directory
+    /// (see [`crate::prefetch::vendor`]) and the render layer points `src` at the vendored copy
+    /// instead of a fetcher, so the generated `Cargo.nix` builds fully offline.
+    pub vendor_dir: Option<PathBuf>,
+}
@@ -9,11 +9,21 @@ use futures::TryStreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use serde::Deserialize;
-use tokio::{fs, io::{self, AsyncWriteExt}, process::Command};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::{self, AsyncWriteExt}, process::Command, sync::Mutex as AsyncMutex};
 
 /// Uses `nix-prefetch` to get the hashes of the sources for the given packages if they come from
 /// crates.io or git.
 ///
+/// crates.io sources whose Cargo.lock checksum is already known skip `nix-prefetch-url`
+/// entirely, since that checksum *is* the sha256 of the exact tarball `nix-prefetch-url` would
+/// otherwise download again; see [`sri_from_cargo_lock_checksum`].
+///
+/// Git sources hosted on github.com, gitlab.com, or bitbucket.org are prefetched via the
+/// provider's archive tarball rather than a full clone unless `config.git_fetcher` opts back
+/// into `pkgs.fetchgit` (e.g. for repositories that need submodules), and are skipped
+/// entirely when rendering with `builtins.fetchGit`; see [`GitFetcher`].
+///
 /// Uses and updates the existing hashes in the `config.crate_hash_json` file.
 pub async fn prefetch(
     config: &GenerateConfig,
@@ -27,12 +37,14 @@ pub async fn prefetch(
     // Only copy used hashes over to the new map.
     let mut hashes: BTreeMap<PackageId, String> = BTreeMap::new();
 
-    // Skip none-registry packages.
+    // Skip none-registry packages. Git sources are skipped too when rendering with
+    // `builtins.fetchGit`, since a fully pinned rev evaluates without a prefetched hash and we
+    // don't want stale git entries lingering in `crate_hashes_json`.
     let mut packages: Vec<&mut CrateDerivation> = crate_derivations
         .iter_mut()
         .filter(|c| match c.source {
             ResolvedSource::CratesIo { sha256: None, .. } => true,
-            ResolvedSource::Git { .. } => true,
+            ResolvedSource::Git { .. } => config.git_fetcher != GitFetcher::BuiltinsFetchGit,
             _ => false,
         })
         .collect();
@@ -50,29 +62,67 @@ pub async fn prefetch(
     );
 
     let old_hashes_ref = &old_hashes;
+    // Shared across all concurrent tasks so a remote is only ever ls-ref'd once per `(url,
+    // ref)` pair, even when many crates pin the same tag.
+    let ref_cache: RefCache = Arc::new(AsyncMutex::new(BTreeMap::new()));
+    // A single pooled client, reused by every crates.io download in this run instead of
+    // spawning `num_cpus * 10` `nix-prefetch-url` child processes.
+    let http_client = reqwest::Client::builder()
+        .user_agent(concat!("crate2nix/", env!("CARGO_PKG_VERSION")))
+        .build()?;
     let tasks = packages.iter().map(|package| {
         let pb = progress_bar.clone();
+        let ref_cache = ref_cache.clone();
+        let http_client = &http_client;
         async move {
-            let sha256 = if let Some(hash) = old_hashes_ref.get(&package.package_id) {
-                hash.trim().to_string()
-            } else {
-                let sha = match package.source {
-                    ResolvedSource::CratesIo { .. } => nix_prefetch_from_crates_io(package).await?,
-                    ResolvedSource::Git { .. } => nix_prefetch_from_git(package).await?,
-                    _ => unreachable!(),
+            let (sha256, git_host): (String, Option<(GitHost, String, String)>) =
+                if let Some(hash) = old_hashes_ref.get(&package.package_id) {
+                    let git_host = match &package.source {
+                        ResolvedSource::Git { url, .. } if config.git_fetcher == GitFetcher::Auto => {
+                            detect_git_host(url)
+                        }
+                        _ => None,
+                    };
+                    (hash.trim().to_string(), git_host)
+                } else {
+                    let result = match &package.source {
+                        // Cargo.lock already records the SHA-256 of the exact `.crate` tarball
+                        // that `nix-prefetch-url` would otherwise download and hash again, so
+                        // reuse it instead of spawning a process per crate.
+                        ResolvedSource::CratesIo {
+                            checksum: Some(checksum),
+                            ..
+                        } => (sri_from_cargo_lock_checksum(checksum)?, None),
+                        ResolvedSource::CratesIo { .. } => {
+                            (nix_prefetch_from_crates_io(http_client, package).await?, None)
+                        }
+                        ResolvedSource::Git { .. } => {
+                            nix_prefetch_from_git(
+                                http_client,
+                                package,
+                                config.git_fetcher,
+                                &ref_cache,
+                            )
+                            .await?
+                        }
+                        _ => unreachable!(),
+                    };
+                    pb.inc(1);
+                    result
                 };
-                pb.inc(1);
-                sha
+            let source = match git_host {
+                Some((host, owner, repo)) => {
+                    package.source.with_git_fetch(sha256.clone(), host, owner, repo)
+                }
+                None => package.source.with_sha256(sha256.clone()),
             };
-            Result::<_, Error>::Ok((
-                package.source.with_sha256(sha256.clone()),
-                package.package_id.clone(),
-                sha256,
-            ))
+            Result::<_, Error>::Ok((source, package.package_id.clone(), sha256))
         }
     });
 
-    // TODO: Is there a good way to choose this number?
+    // Bounds how many crates.io downloads and git prefetches are in flight at once; acts as
+    // the connection limit now that crates.io fetches go through a pooled `reqwest::Client`
+    // instead of a spawned process per crate.
     let n_concurrent_tasks = num_cpus::get() * 10;
     let triples: Vec<_> = futures::stream::iter(tasks)
         .buffer_unordered(n_concurrent_tasks)
@@ -94,9 +144,156 @@ pub async fn prefetch(
         );
     }
 
+    if let Some(vendor_dir) = &config.vendor_dir {
+        vendor(crate_derivations, vendor_dir).await?;
+    }
+
     Ok(hashes)
 }
 
+/// Downloads every crates.io and git source referenced by `crate_derivations` into
+/// `vendor_dir`, laid out as `<name>-<version>` directories, so that the generated `Cargo.nix`
+/// can point its `src` attributes at local paths and `nix build` can run fully offline.
+///
+/// This mirrors the hashing pass in [`prefetch`] but keeps the downloaded bytes around instead
+/// of discarding them once the nix hash is known, and reuses the same concurrency harness and
+/// progress bar.
+pub async fn vendor(crate_derivations: &[CrateDerivation], vendor_dir: &std::path::Path) -> Result<(), Error> {
+    fs::create_dir_all(vendor_dir).await?;
+
+    let progress_bar = Arc::new(ProgressBar::new(crate_derivations.len().try_into()?));
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
+            .progress_chars("#>-"),
+    );
+
+    // One pooled client for every crates.io download in this pass, built the same way as the
+    // one in `prefetch`, rather than shelling out to `curl`/`sha256sum` per crate.
+    let http_client = reqwest::Client::builder()
+        .user_agent(concat!("crate2nix/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+    let tasks = crate_derivations.iter().map(|package| {
+        let pb = progress_bar.clone();
+        let http_client = &http_client;
+        let dest = vendor_dir.join(format!("{}-{}", package.crate_name, package.version));
+        async move {
+            match &package.source {
+                ResolvedSource::CratesIo { checksum, .. } => {
+                    vendor_crates_io(http_client, package, checksum.as_deref(), &dest).await?;
+                }
+                ResolvedSource::Git { url, rev, .. } => {
+                    vendor_git(url, rev, &dest).await?;
+                }
+                // Local and other sources already live on disk; nothing to vendor.
+                _ => {}
+            }
+            pb.inc(1);
+            Result::<_, Error>::Ok(())
+        }
+    });
+
+    let n_concurrent_tasks = num_cpus::get() * 10;
+    futures::stream::iter(tasks)
+        .buffer_unordered(n_concurrent_tasks)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    Ok(())
+}
+
+/// Downloads and unpacks a `.crate` tarball into `dest`, verifying it against the Cargo.lock
+/// checksum (when one is known) before extracting.
+///
+/// The download and hash are done in-process with `http_client`/`sha2`, the same as
+/// `nix_prefetch_from_crates_io`; only the actual tarball extraction still shells out, since
+/// there's no pure-Rust tar dependency in this pipeline yet.
+async fn vendor_crates_io(
+    http_client: &reqwest::Client,
+    crate_derivation: &CrateDerivation,
+    expected_checksum: Option<&str>,
+    dest: &std::path::Path,
+) -> Result<(), Error> {
+    if fs::metadata(dest).await.is_ok() {
+        return Ok(());
+    }
+
+    let url = format!(
+        "https://crates.io/api/v1/crates/{}/{}/download",
+        crate_derivation.crate_name, crate_derivation.version
+    );
+    let dest_str = dest
+        .to_str()
+        .ok_or_else(|| format_err!("non-UTF8 vendor path: {}", dest.display()))?;
+    // `dest.with_extension(...)` would treat the last dot-separated segment of `<name>-<version>`
+    // as a file extension, colliding e.g. `foo-1.0.0` and `foo-1.0.5` into the same
+    // `foo-1.0.crate.tmp`. Append the suffix to the full file name instead.
+    let tarball_name = format!(
+        "{}.crate.tmp",
+        dest.file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| format_err!("non-UTF8 vendor path: {}", dest.display()))?
+    );
+    let tarball = dest.with_file_name(tarball_name);
+    let tarball_str = tarball
+        .to_str()
+        .ok_or_else(|| format_err!("non-UTF8 vendor path: {}", tarball.display()))?;
+
+    let response = http_client.get(&url).send().await?.error_for_status()?;
+    let mut stream = response.bytes_stream();
+    let mut hasher = Sha256::new();
+    let mut file = fs::File::create(&tarball).await?;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    drop(file);
+
+    if let Some(expected) = expected_checksum {
+        let actual = hex_encode(&hasher.finalize());
+        if actual != expected {
+            fs::remove_file(&tarball).await?;
+            bail!(
+                "checksum mismatch vendoring {}-{}: expected {}, got {}",
+                crate_derivation.crate_name,
+                crate_derivation.version,
+                expected,
+                actual
+            );
+        }
+    }
+
+    fs::create_dir_all(dest).await?;
+    get_command_output(
+        "tar",
+        &["xzf", tarball_str, "-C", dest_str, "--strip-components=1"],
+    )
+    .await?;
+    fs::remove_file(&tarball).await?;
+    Ok(())
+}
+
+/// Lowercase-hex-encodes `bytes`, for comparing a downloaded tarball's digest against the hex
+/// checksum Cargo.lock records.
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Checks out `rev` of the git repository at `url` into `dest` for offline vendoring.
+async fn vendor_git(url: &str, rev: &str, dest: &std::path::Path) -> Result<(), Error> {
+    if fs::metadata(dest).await.is_ok() {
+        return Ok(());
+    }
+    let dest_str = dest
+        .to_str()
+        .ok_or_else(|| format_err!("non-UTF8 vendor path: {}", dest.display()))?;
+    get_command_output("git", &["clone", "--quiet", url, dest_str]).await?;
+    get_command_output("git", &["-C", dest_str, "checkout", "--quiet", rev]).await?;
+    Ok(())
+}
+
 async fn get_command_output(cmd: &str, args: &[&str]) -> Result<String, Error> {
     let output = Command::new(cmd)
         .args(args)
@@ -119,23 +316,128 @@ async fn get_command_output(cmd: &str, args: &[&str]) -> Result<String, Error> {
         .map_err(|_e| format_err!("output of '{} {}' is not UTF8!", cmd, args.join(" ")))
 }
 
-/// Invoke `nix-prefetch` for the given `package` and return the hash.
-async fn nix_prefetch_from_crates_io(crate_derivation: &CrateDerivation) -> Result<String, Error> {
+/// Converts a Cargo.lock `checksum` (64 lowercase hex characters) into the SRI-style
+/// `sha256-<base64>` hash that `fetchurl`/`fetchzip` accept for their `hash` attribute.
+///
+/// Yanked, path, and git sources have no lockfile checksum and must keep going through
+/// `nix_prefetch_from_crates_io`/`nix_prefetch_from_git`; this only short-circuits the
+/// common case of a checksummed crates.io dependency.
+fn sri_from_cargo_lock_checksum(checksum: &str) -> Result<String, Error> {
+    if checksum.len() != 64 || !checksum.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!(
+            "'{}' is not a 64 character hex-encoded sha256 checksum",
+            checksum
+        );
+    }
+
+    let bytes: Vec<u8> = (0..32)
+        .map(|i| u8::from_str_radix(&checksum[i * 2..i * 2 + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|e| format_err!("invalid hex checksum '{}': {}", checksum, e))?;
+
+    Ok(format!("sha256-{}", base64_encode(&bytes)))
+}
+
+/// Minimal standard-alphabet base64 encoder (with padding), used to turn a raw sha256 digest
+/// into the SRI hash form nix's `fetchurl`/`fetchzip` expect.
+fn base64_encode(bytes: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b0 = chunk[0];
+        let b1 = *chunk.get(1).unwrap_or(&0);
+        let b2 = *chunk.get(2).unwrap_or(&0);
+        out.push(TABLE[(b0 >> 2) as usize] as char);
+        out.push(TABLE[(((b0 & 0x03) << 4) | (b1 >> 4)) as usize] as char);
+        out.push(if chunk.len() > 1 {
+            TABLE[(((b1 & 0x0f) << 2) | (b2 >> 6)) as usize] as char
+        } else {
+            '='
+        });
+        out.push(if chunk.len() > 2 {
+            TABLE[(b2 & 0x3f) as usize] as char
+        } else {
+            '='
+        });
+    }
+    out
+}
+
+/// Streams the crate's `.crate` tarball from crates.io and hashes it incrementally, rather
+/// than shelling out to `nix-prefetch-url` and letting it perform (and discard) the same
+/// download. Retries with exponential backoff on timeouts and 5xx responses, which a spawned
+/// `nix-prefetch-url` process gives us no way to do.
+async fn nix_prefetch_from_crates_io(
+    http_client: &reqwest::Client,
+    crate_derivation: &CrateDerivation,
+) -> Result<String, Error> {
     let url = format!(
         "https://crates.io/api/v1/crates/{}/{}/download",
         crate_derivation.crate_name, crate_derivation.version
     );
 
-    let cmd = "nix-prefetch-url";
-    let args = [
-        &url,
-        "--name",
-        &format!(
-            "{}-{}",
-            crate_derivation.crate_name, crate_derivation.version
-        ),
-    ];
-    get_command_output(cmd, &args).await
+    const MAX_ATTEMPTS: u32 = 5;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match download_and_hash(http_client, &url).await {
+            Ok(hash) => return Ok(hash),
+            Err(e) if attempt < MAX_ATTEMPTS && is_retryable(&e) => {
+                let backoff = std::time::Duration::from_millis(200 * 2u64.pow(attempt - 1));
+                eprintln!(
+                    "{} (attempt {}/{}), retrying {} in {:?}",
+                    e, attempt, MAX_ATTEMPTS, url, backoff
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Downloads `url` and returns the nix base32 encoding of its sha256 digest.
+async fn download_and_hash(http_client: &reqwest::Client, url: &str) -> Result<String, Error> {
+    let response = http_client.get(url).send().await?.error_for_status()?;
+    let mut stream = response.bytes_stream();
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.next().await {
+        hasher.update(&chunk?);
+    }
+    Ok(nix_base32_encode(&hasher.finalize()))
+}
+
+/// Whether a download failure is worth retrying, i.e. a timeout or a server-side (5xx) error
+/// rather than something retrying won't fix (a 404, a malformed URL, ...).
+fn is_retryable(error: &Error) -> bool {
+    error.downcast_ref::<reqwest::Error>().map_or(false, |e| {
+        e.is_timeout() || e.status().map_or(false, |status| status.is_server_error())
+    })
+}
+
+/// Encodes `bytes` using nix's flavor of base32 (the format `nix-prefetch-url` prints), so the
+/// hash produced here is interchangeable with the one a spawned `nix-prefetch-url` would have
+/// returned.
+///
+/// Nix treats `bytes[0]` as the *least*-significant byte of the number being encoded (the
+/// reverse of how e.g. a SHA-256 digest is normally printed), and emits the most significant
+/// base32 digit first. Indexing from the front with `bytes[byte]`/`bytes[byte + 1]` below (not
+/// `bytes[hash_len - 1 - byte]`) is what gives that convention.
+fn nix_base32_encode(bytes: &[u8]) -> String {
+    const CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+    let hash_len = bytes.len();
+    let len = (hash_len * 8 + 4) / 5;
+    let mut out = vec![0u8; len];
+    for n in 0..len {
+        let bit_pos = n * 5;
+        let byte = bit_pos / 8;
+        let bit = bit_pos % 8;
+        let mut c: u16 = (bytes[byte] as u16) >> bit;
+        if byte + 1 < hash_len && bit > 3 {
+            c |= (bytes[byte + 1] as u16) << (8 - bit);
+        }
+        out[len - 1 - n] = CHARS[(c & 0x1f) as usize];
+    }
+    String::from_utf8(out).expect("nix base32 alphabet is ASCII")
 }
 
 /// A struct used to contain the output returned by `nix-prefetch-git`.
@@ -148,27 +450,339 @@ struct NixPrefetchGitInfo {
     sha256: String,
 }
 
-async fn nix_prefetch_from_git(crate_derivation: &CrateDerivation) -> Result<String, Error> {
+/// Controls which nix fetcher `nix_prefetch_from_git` targets for `ResolvedSource::Git`
+/// dependencies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GitFetcher {
+    /// Use `pkgs.fetchFromGitHub`/`fetchFromGitLab`/`fetchFromBitbucket` for repositories
+    /// hosted on the corresponding provider, falling back to `pkgs.fetchgit` for everything
+    /// else.
+    Auto,
+    /// Always use `pkgs.fetchgit`, e.g. because a repository relies on submodules that the
+    /// hosting providers' archive endpoints omit.
+    FetchGit,
+    /// Emit `builtins.fetchGit { url; rev; ref; submodules = true; }` instead of a prefetched
+    /// derivation. Only valid for fully pinned revs, which `builtins.fetchGit` can evaluate
+    /// without a NAR hash; `prefetch` skips git sources entirely in this mode.
+    BuiltinsFetchGit,
+}
+
+impl Default for GitFetcher {
+    fn default() -> Self {
+        GitFetcher::Auto
+    }
+}
+
+/// A well-known git hosting provider whose archive endpoint we can hit directly instead of
+/// cloning, mirroring the fetcher selection nurl performs.
+///
+/// `pub(crate)` rather than private: [`crate::resolve::ResolvedSource::Git`] carries this
+/// alongside the detected `owner`/`repo` so the render layer can pick the matching fetcher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum GitHost {
+    GitHub,
+    GitLab,
+    Bitbucket,
+}
+
+impl GitHost {
+    /// The URL of the tarball nix's archive-based fetcher would download for `owner`/`repo` at
+    /// `rev`. This is what [`prefetch_archive_hash`] downloads and hashes instead of cloning.
+    fn archive_url(self, owner: &str, repo: &str, rev: &str) -> String {
+        match self {
+            GitHost::GitHub => format!(
+                "https://github.com/{}/{}/archive/{}.tar.gz",
+                owner, repo, rev
+            ),
+            GitHost::GitLab => format!(
+                "https://gitlab.com/{}/{}/-/archive/{}/{}-{}.tar.gz",
+                owner, repo, rev, repo, rev
+            ),
+            GitHost::Bitbucket => format!(
+                "https://bitbucket.org/{}/{}/get/{}.tar.gz",
+                owner, repo, rev
+            ),
+        }
+    }
+}
+
+/// Detects whether `url` points at a repository hosted on github.com, gitlab.com, or
+/// bitbucket.org and, if so, extracts its `owner`/`repo`.
+fn detect_git_host(url: &str) -> Option<(GitHost, String, String)> {
+    let without_scheme = url
+        .trim_end_matches(".git")
+        .splitn(2, "://")
+        .last()
+        .unwrap_or(url);
+    // Strip a leading `user@` from scp-like git URLs (`git@github.com:owner/repo`).
+    let without_scheme = without_scheme
+        .rsplitn(2, '@')
+        .next()
+        .unwrap_or(without_scheme);
+    let without_scheme = without_scheme.replacen(':', "/", 1);
+
+    let mut parts = without_scheme.splitn(2, '/');
+    let host = parts.next()?;
+    let path = parts.next()?.trim_matches('/');
+    let mut path_parts = path.splitn(2, '/');
+    let owner = path_parts.next()?;
+    let repo = path_parts.next()?;
+
+    let git_host = match host {
+        "github.com" => GitHost::GitHub,
+        "gitlab.com" => GitHost::GitLab,
+        "bitbucket.org" => GitHost::Bitbucket,
+        _ => return None,
+    };
+
+    Some((git_host, owner.to_string(), repo.to_string()))
+}
+
+/// Cache of `(repository url, requested ref)` -> resolved commit id, shared across the
+/// concurrent prefetch tasks in a single [`prefetch`] run.
+type RefCache = Arc<AsyncMutex<BTreeMap<(String, String), String>>>;
+
+/// Resolves `r#ref` (a branch, tag, or other ref) against the remote at `url` to a concrete
+/// commit object id, peeling annotated tags down to the commit they point at.
+///
+/// `nix-prefetch-git --branch-name` only works when `r#ref` actually names a branch; tags and
+/// other refs have to be passed to `--rev` as a concrete commit instead, so we resolve them
+/// ourselves with `gix`'s remote ref-listing support rather than relying on `nix-prefetch-git`
+/// to guess. Results are cached per `(url, ref)` so the concurrent task set doesn't re-query
+/// the same remote for every crate that pins the same tag.
+async fn resolve_git_ref(cache: &RefCache, url: &str, r#ref: &str) -> Result<String, Error> {
+    let key = (url.to_string(), r#ref.to_string());
+    if let Some(rev) = cache.lock().await.get(&key) {
+        return Ok(rev.clone());
+    }
+
+    let url = url.to_string();
+    let wanted = r#ref.to_string();
+    let rev = tokio::task::spawn_blocking(move || -> Result<String, Error> {
+        // `remote_at`/`connect`/`ref_map` need a repository to hang the remote's config off
+        // of; a scratch bare repo in a tempdir is enough, we never fetch any objects into it.
+        let scratch = tempfile::tempdir()
+            .map_err(|e| format_err!("creating scratch repo for '{}': {}", url, e))?;
+        let repo = gix::init_bare(scratch.path())
+            .map_err(|e| format_err!("initializing scratch repo for '{}': {}", url, e))?;
+        let remote = repo
+            .remote_at(url.as_str())
+            .map_err(|e| format_err!("configuring remote '{}': {}", url, e))?;
+        let connection = remote
+            .connect(gix::remote::Direction::Fetch)
+            .map_err(|e| format_err!("connecting to remote '{}': {}", url, e))?;
+        let ref_map = connection
+            .ref_map(gix::progress::Discard, Default::default())
+            .map_err(|e| format_err!("listing refs for '{}': {}", url, e))?;
+
+        let wanted_names = [
+            format!("refs/tags/{}", wanted),
+            format!("refs/heads/{}", wanted),
+            wanted.clone(),
+        ];
+
+        ref_map
+            .remote_refs
+            .iter()
+            .find_map(|r| {
+                use gix::protocol::handshake::Ref;
+                // Advertised refs come back as one of these three shapes rather than a
+                // struct; `Peeled::object` is already the commit a tag points at (`tag` is
+                // the tag object's own id), so matching all three the same way always gives
+                // us a commit id.
+                let (full_ref_name, object) = match r {
+                    Ref::Peeled { full_ref_name, object, .. } => (full_ref_name, object),
+                    Ref::Direct { full_ref_name, object } => (full_ref_name, object),
+                    Ref::Symbolic { full_ref_name, object, .. } => (full_ref_name, object),
+                };
+                wanted_names
+                    .iter()
+                    .any(|name| full_ref_name.to_string() == *name)
+                    .then(|| object.to_string())
+            })
+            .ok_or_else(|| format_err!("ref '{}' not found on remote '{}'", wanted, url))
+    })
+    .await
+    .map_err(|e| format_err!("ref-resolution task for '{}' panicked: {}", url, e))??;
+
+    cache.lock().await.insert(key, rev.clone());
+    Ok(rev)
+}
+
+/// Whether `rev` already looks like a full git commit id (40 hex characters), in which case
+/// there's nothing to resolve over the network.
+fn is_full_commit_id(rev: &str) -> bool {
+    rev.len() == 40 && rev.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Downloads the archive tarball at `archive_url` and returns the nix hash of its contents with
+/// the single top-level directory (`<repo>-<rev>/`) stripped.
+///
+/// `fetchFromGitHub`/`fetchFromGitLab`/`fetchFromBitbucket` are `fetchzip` with `stripRoot =
+/// true`, which drops that wrapper directory before computing the derivation's hash; hashing the
+/// archive as downloaded (e.g. via `nix-prefetch-url --unpack`) includes it, so the hash would
+/// never match what the fetcher actually produces. Reproduce `stripRoot` ourselves with `tar
+/// --strip-components=1` before hashing the result with `nix-hash`, the same way `nurl` does.
+async fn prefetch_archive_hash(http_client: &reqwest::Client, archive_url: &str) -> Result<String, Error> {
+    let scratch = tempfile::tempdir()
+        .map_err(|e| format_err!("creating scratch dir for '{}': {}", archive_url, e))?;
+    let tarball = scratch.path().join("archive.tar.gz");
+    let tarball_str = tarball
+        .to_str()
+        .ok_or_else(|| format_err!("non-UTF8 scratch path: {}", tarball.display()))?;
+    let extracted = scratch.path().join("extracted");
+    fs::create_dir_all(&extracted).await?;
+    let extracted_str = extracted
+        .to_str()
+        .ok_or_else(|| format_err!("non-UTF8 scratch path: {}", extracted.display()))?;
+
+    let response = http_client.get(archive_url).send().await?.error_for_status()?;
+    let mut stream = response.bytes_stream();
+    let mut file = fs::File::create(&tarball).await?;
+    while let Some(chunk) = stream.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await?;
+    drop(file);
+
+    get_command_output(
+        "tar",
+        &["xzf", tarball_str, "-C", extracted_str, "--strip-components=1"],
+    )
+    .await?;
+
+    get_command_output("nix-hash", &["--type", "sha256", "--base32", extracted_str]).await
+}
+
+/// Prefetches a git dependency and returns its nix hash, along with the hosting provider and
+/// `owner`/`repo` detected from its URL when one applies. The caller threads that host info
+/// back onto the `ResolvedSource` so a render layer can emit `fetchFromGitHub`/
+/// `fetchFromGitLab`/`fetchFromBitbucket` instead of a generic `fetchgit`.
+async fn nix_prefetch_from_git(
+    http_client: &reqwest::Client,
+    crate_derivation: &CrateDerivation,
+    git_fetcher: GitFetcher,
+    ref_cache: &RefCache,
+) -> Result<(String, Option<(GitHost, String, String)>), Error> {
     if let ResolvedSource::Git {
         url, rev, r#ref, ..
     } = &crate_derivation.source
     {
-        let cmd = "nix-prefetch-git";
-        let mut args = vec!["--url", url.as_str(), "--fetch-submodules", "--rev", rev];
+        // Cargo.lock already records a concrete commit in `rev` for every git dependency; only
+        // fall back to resolving `r#ref` (or, without one, `rev` itself) over the network when
+        // `rev` isn't already a full commit id. That lookup matches advertised ref names only:
+        // a short commit hash is not expanded, and fails unless a ref of that name exists.
+        // Resolving `r#ref` unconditionally
+        // would silently swap the locked commit for the remote's *current* tip of e.g. `branch
+        // = "main"`, breaking reproducibility.
+        let resolved_rev = if is_full_commit_id(rev) {
+            rev.clone()
+        } else {
+            resolve_git_ref(ref_cache, url, r#ref.as_deref().unwrap_or(rev)).await?
+        };
 
-        // TODO: --branch-name isn't documented in nix-prefetch-git --help
-        // TODO: Consider the case when ref *isn't* a branch. You have to pass
-        // that to `--rev` instead. This seems like a limitation of nix-prefetch-git.
-        if let Some(r#ref) = r#ref {
-            args.extend_from_slice(&["--branch-name", r#ref]);
+        let detected_host = if git_fetcher == GitFetcher::Auto {
+            detect_git_host(url)
+        } else {
+            None
+        };
+
+        if let Some((host, owner, repo)) = &detected_host {
+            // The provider's archive tarball is smaller, faster, and far more cache-friendly
+            // than a full clone, so prefer it whenever we can.
+            let archive_url = host.archive_url(owner, repo, &resolved_rev);
+            let sha256 = prefetch_archive_hash(http_client, &archive_url).await?;
+            return Ok((sha256, detected_host));
         }
 
+        let cmd = "nix-prefetch-git";
+        let args = [
+            "--url",
+            url.as_str(),
+            "--fetch-submodules",
+            "--rev",
+            &resolved_rev,
+        ];
+
         let json = get_command_output(cmd, &args).await?;
         let prefetch_info: NixPrefetchGitInfo = serde_json::from_str(&json)?;
-        Ok(prefetch_info.sha256)
+        Ok((prefetch_info.sha256, None))
     } else {
         Err(format_err!(
             "Invalid source type for pre-fetching using git"
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nix_base32_encode_matches_known_vector() {
+        // sha256("") = e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855
+        let digest = [
+            0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f,
+            0xb9, 0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b,
+            0x78, 0x52, 0xb8, 0x55,
+        ];
+        assert_eq!(
+            nix_base32_encode(&digest),
+            "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+    }
+
+    #[test]
+    fn sri_from_cargo_lock_checksum_matches_known_vector() {
+        // sha256("") again, this time taken as the Cargo.lock `checksum` field and converted
+        // to the SRI form fetchurl/fetchzip accept for their `hash` attribute.
+        let checksum = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(
+            sri_from_cargo_lock_checksum(checksum).unwrap(),
+            "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+    }
+
+    #[test]
+    fn sri_from_cargo_lock_checksum_rejects_malformed_input() {
+        assert!(sri_from_cargo_lock_checksum("not-a-checksum").is_err());
+        assert!(sri_from_cargo_lock_checksum("deadbeef").is_err());
+    }
+
+    #[test]
+    fn detect_git_host_recognizes_https_urls() {
+        assert_eq!(
+            detect_git_host("https://github.com/rust-lang/cargo"),
+            Some((GitHost::GitHub, "rust-lang".to_string(), "cargo".to_string()))
+        );
+        assert_eq!(
+            detect_git_host("https://github.com/rust-lang/cargo.git"),
+            Some((GitHost::GitHub, "rust-lang".to_string(), "cargo".to_string()))
+        );
+    }
+
+    #[test]
+    fn detect_git_host_recognizes_scp_like_urls() {
+        assert_eq!(
+            detect_git_host("git@github.com:rust-lang/cargo.git"),
+            Some((GitHost::GitHub, "rust-lang".to_string(), "cargo".to_string()))
+        );
+    }
+
+    #[test]
+    fn detect_git_host_recognizes_gitlab_subgroups() {
+        assert_eq!(
+            detect_git_host("https://gitlab.com/group/subgroup/project"),
+            Some((
+                GitHost::GitLab,
+                "group".to_string(),
+                "subgroup/project".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn detect_git_host_ignores_unknown_hosts() {
+        assert_eq!(detect_git_host("https://example.com/owner/repo"), None);
+    }
+}
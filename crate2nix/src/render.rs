@@ -0,0 +1,120 @@
+//! Renders resolved crate sources into the nix expressions used by the generated `Cargo.nix`.
+
+use std::path::Path;
+
+use crate::prefetch::{GitFetcher, GitHost};
+use crate::resolve::{CrateDerivation, ResolvedSource};
+
+/// Escapes `s` for use inside a double-quoted nix string literal.
+pub fn escape_nix_string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "\\$")
+    )
+}
+
+/// Renders the nix expression used as a crate derivation's `src`, choosing the fetcher that
+/// matches how `prefetch` actually obtained (or deliberately skipped) the source's hash.
+///
+/// When `vendor_dir` is set, crates.io and git sources instead point at the directory
+/// [`crate::prefetch::vendor`] already downloaded them into (relative to the generated
+/// `Cargo.nix`), so the build can run fully offline; `git_fetcher` and the prefetched hash are
+/// irrelevant in that case since there's nothing left to fetch.
+pub fn render_src(
+    crate_derivation: &CrateDerivation,
+    git_fetcher: GitFetcher,
+    vendor_dir: Option<&Path>,
+) -> String {
+    if let Some(vendor_dir) = vendor_dir {
+        if matches!(
+            crate_derivation.source,
+            ResolvedSource::CratesIo { .. } | ResolvedSource::Git { .. }
+        ) {
+            return format!(
+                "./{}/{}-{}",
+                vendor_dir.display(),
+                crate_derivation.crate_name,
+                crate_derivation.version
+            );
+        }
+    }
+
+    match &crate_derivation.source {
+        ResolvedSource::CratesIo { sha256: Some(hash), .. } => format!(
+            "fetchurl {{ url = {url}; {hash_attr} = {hash}; }}",
+            url = "\"${crateUrl}\"",
+            hash_attr = hash_attribute(hash),
+            hash = escape_nix_string(hash),
+        ),
+        ResolvedSource::CratesIo { sha256: None, .. } => {
+            "fetchurl { url = \"${crateUrl}\"; }".to_string()
+        }
+        ResolvedSource::Git {
+            url,
+            rev,
+            r#ref,
+            host,
+            sha256,
+        } => render_git_src(url, rev, r#ref.as_deref(), host.as_ref(), sha256.as_deref(), git_fetcher),
+        ResolvedSource::Local { path } => escape_nix_string(path),
+    }
+}
+
+/// `sha256 = "..."` for the legacy base32 form, or `hash = "sha256-..."` for SRI; both are
+/// accepted by `fetchurl`/`fetchzip`/`fetchFromGitHub` and friends, so the render layer just
+/// has to match whichever form `prefetch` produced (see `sri_from_cargo_lock_checksum` and
+/// `nix_base32_encode` in `prefetch`).
+fn hash_attribute(hash: &str) -> &'static str {
+    if hash.starts_with("sha256-") {
+        "hash"
+    } else {
+        "sha256"
+    }
+}
+
+fn render_git_src(
+    url: &str,
+    rev: &str,
+    r#ref: Option<&str>,
+    host: Option<&(GitHost, String, String)>,
+    sha256: Option<&str>,
+    git_fetcher: GitFetcher,
+) -> String {
+    if git_fetcher == GitFetcher::BuiltinsFetchGit {
+        return format!(
+            "builtins.fetchGit {{ url = {url}; rev = {rev}; {ref_attr} submodules = true; }}",
+            url = escape_nix_string(url),
+            rev = escape_nix_string(rev),
+            ref_attr = r#ref
+                .map(|r| format!("ref = {};", escape_nix_string(r)))
+                .unwrap_or_default(),
+        );
+    }
+
+    let sha256 = sha256.unwrap_or_default();
+
+    if let Some((provider, owner, repo)) = host {
+        let fetcher = match provider {
+            GitHost::GitHub => "fetchFromGitHub",
+            GitHost::GitLab => "fetchFromGitLab",
+            GitHost::Bitbucket => "fetchFromBitbucket",
+        };
+        return format!(
+            "{fetcher} {{ owner = {owner}; repo = {repo}; rev = {rev}; {hash_attr} = {hash}; }}",
+            fetcher = fetcher,
+            owner = escape_nix_string(owner),
+            repo = escape_nix_string(repo),
+            rev = escape_nix_string(rev),
+            hash_attr = hash_attribute(sha256),
+            hash = escape_nix_string(sha256),
+        );
+    }
+
+    format!(
+        "fetchgit {{ url = {url}; rev = {rev}; {hash_attr} = {hash}; fetchSubmodules = true; }}",
+        url = escape_nix_string(url),
+        rev = escape_nix_string(rev),
+        hash_attr = hash_attribute(sha256),
+        hash = escape_nix_string(sha256),
+    )
+}
@@ -0,0 +1,91 @@
+//! Types describing, for each crate in the resolved dependency graph, what its nix derivation
+//! needs to build: the crate's identity and where its source comes from.
+
+use cargo_metadata::PackageId;
+
+use crate::prefetch::GitHost;
+
+/// A single crate's resolved nix derivation inputs.
+#[derive(Debug, Clone)]
+pub struct CrateDerivation {
+    pub package_id: PackageId,
+    pub crate_name: String,
+    pub version: String,
+    pub source: ResolvedSource,
+}
+
+/// Where a crate's source comes from, and what's needed to fetch it in the generated
+/// `Cargo.nix`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ResolvedSource {
+    /// A crate published to crates.io.
+    CratesIo {
+        /// The nix hash of the `.crate` tarball (base32 or, when derived from `checksum`,
+        /// SRI), once known.
+        sha256: Option<String>,
+        /// The SHA-256 checksum Cargo.lock already recorded for this crate, if any.
+        ///
+        /// That checksum is the sha256 of the exact tarball `nix-prefetch-url` would
+        /// otherwise download and hash again, so `prefetch` turns it directly into an SRI
+        /// hash instead of re-fetching.
+        checksum: Option<String>,
+    },
+    /// A crate pulled from a git repository.
+    Git {
+        url: String,
+        rev: String,
+        r#ref: Option<String>,
+        /// The hosting provider detected from `url`, together with its `owner`/`repo`, when
+        /// `url` points at github.com, gitlab.com, or bitbucket.org. Lets the render layer
+        /// emit `fetchFromGitHub`/`fetchFromGitLab`/`fetchFromBitbucket` instead of a generic
+        /// `fetchgit`.
+        host: Option<(GitHost, String, String)>,
+        sha256: Option<String>,
+    },
+    /// A crate referenced via a local path dependency.
+    Local { path: String },
+}
+
+impl ResolvedSource {
+    /// Returns a copy of this source with its nix hash set to `sha256`.
+    pub fn with_sha256(&self, sha256: String) -> ResolvedSource {
+        match self {
+            ResolvedSource::CratesIo { checksum, .. } => ResolvedSource::CratesIo {
+                sha256: Some(sha256),
+                checksum: checksum.clone(),
+            },
+            ResolvedSource::Git {
+                url, rev, r#ref, host, ..
+            } => ResolvedSource::Git {
+                url: url.clone(),
+                rev: rev.clone(),
+                r#ref: r#ref.clone(),
+                host: host.clone(),
+                sha256: Some(sha256),
+            },
+            ResolvedSource::Local { path } => ResolvedSource::Local { path: path.clone() },
+        }
+    }
+
+    /// Returns a copy of this `Git` source with its nix hash set to `sha256` and its detected
+    /// hosting provider set to `host`/`owner`/`repo`, so the render layer can choose
+    /// `fetchFromGitHub`/`fetchFromGitLab`/`fetchFromBitbucket` over `fetchgit`.
+    pub fn with_git_fetch(
+        &self,
+        sha256: String,
+        host: GitHost,
+        owner: String,
+        repo: String,
+    ) -> ResolvedSource {
+        match self {
+            ResolvedSource::Git { url, rev, r#ref, .. } => ResolvedSource::Git {
+                url: url.clone(),
+                rev: rev.clone(),
+                r#ref: r#ref.clone(),
+                host: Some((host, owner, repo)),
+                sha256: Some(sha256),
+            },
+            other => other.with_sha256(sha256),
+        }
+    }
+}